@@ -0,0 +1,272 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Builds the input pairs for a single block's changes trie: the
+//! `{ key => extrinsics }` entries for a regular block, plus - on a digest
+//! boundary - the `{ key => blocks }` / `{ key => lower-digest-blocks }`
+//! entries built by scanning the span of blocks/digests the digest covers.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use hashdb::{HashDB, DBValue};
+use patricia_trie::{TrieDB, Trie};
+use trie_backend::TrieH256;
+use changes_trie::{Configuration, DigestBuildCache, Storage};
+use changes_trie::build_iterator::{digest_build_iterator, DigestItem};
+use changes_trie::cache::LocalTrieCache;
+use changes_trie::input::{InputPair, untagged_key};
+use overlayed_changes::OverlayedChanges;
+
+/// Builds the input pairs for the changes trie of `block`.
+///
+/// Returns `None` if there's nothing to commit: no key in `changes` has any
+/// extrinsics recorded, and no digest entry was produced (`storage` is
+/// `None`, `block` isn't a digest boundary, or nothing changed in the span
+/// the digest covers). Returns `Err` if a digest's span
+/// turns out to cover a block/digest whose trie can't be opened - e.g. it
+/// was pruned before this digest was built over it - rather than silently
+/// treating the unreadable block as "nothing changed".
+///
+/// `digest_cache`, if given, lets a digest covering already-built
+/// lower-level digests reuse their cached key sets instead of re-reading
+/// their tries - see `DigestBuildCache`.
+pub fn prepare_input(
+	storage: Option<Arc<Storage>>,
+	config: &Configuration,
+	changes: &OverlayedChanges,
+	block: u64,
+	cache: Option<&LocalTrieCache>,
+	digest_cache: Option<&DigestBuildCache>,
+) -> Result<Option<Vec<InputPair>>, String> {
+	let mut pairs = Vec::new();
+
+	for (key, extrinsics) in changes.changes() {
+		if !extrinsics.is_empty() {
+			pairs.push(InputPair::ExtrinsicIndex(key, extrinsics));
+		}
+	}
+
+	if let Some(storage) = storage {
+		if let Some((digest_interval, digest_block)) = digest_boundary(config, block) {
+			let span_begin = digest_block + 1 - digest_interval;
+			let mut changed_keys = BTreeMap::new();
+			collect_digest_entries(
+				&*storage, config, span_begin, digest_block - 1, cache, digest_cache, &mut changed_keys,
+			)?;
+			for (key, blocks) in changed_keys {
+				pairs.push(InputPair::DigestIndex(key, blocks.into_iter().collect()));
+			}
+		}
+	}
+
+	Ok(if pairs.is_empty() {
+		None
+	} else {
+		Some(pairs)
+	})
+}
+
+/// If `block` is the last block of some digest's span, returns that
+/// digest's `(digest_interval, digest_block)` - `digest_block == block`.
+///
+/// Computed directly from `block` (mirroring `build_iterator::highest_digest_at`,
+/// but checking the span *ending* at `block` rather than one *starting* at it)
+/// instead of replaying `digest_build_iterator` from genesis - the latter would
+/// be O(block) work and allocation on every single block.
+fn digest_boundary(config: &Configuration, block: u64) -> Option<(u64, u64)> {
+	if let Some(skewed) = config.skewed_digest {
+		if skewed.end == block && skewed.begin <= skewed.end {
+			return Some((skewed.end - skewed.begin + 1, block));
+		}
+	}
+
+	if block == 0 || config.digest_interval <= 1 || config.digest_levels == 0 {
+		return None;
+	}
+
+	(1..=config.digest_levels).rev().filter_map(|level| {
+		let digest_interval = config.digest_interval.checked_pow(level as u32)?;
+		if digest_interval == 0 || block % digest_interval != 0 {
+			return None;
+		}
+
+		Some((digest_interval, block))
+	}).next()
+}
+
+/// Scans `[span_begin, span_end]` - the blocks/lower-digests a digest is
+/// built out of - recording, for every storage key touched anywhere in the
+/// span, the immediate child block/digest number it was touched in.
+///
+/// When a child of the span is itself a digest, its key set is taken
+/// straight from `digest_cache` when available (O(1) - the work it took to
+/// build that digest is never repeated), falling back to reading its trie
+/// directly on a cache miss (e.g. it was built before caching existed, or
+/// its cache entry was since evicted).
+fn collect_digest_entries<S: Storage + ?Sized>(
+	storage: &S,
+	config: &Configuration,
+	span_begin: u64,
+	span_end: u64,
+	cache: Option<&LocalTrieCache>,
+	digest_cache: Option<&DigestBuildCache>,
+	changed_keys: &mut BTreeMap<Vec<u8>, BTreeSet<u64>>,
+) -> Result<(), String> {
+	if span_begin > span_end {
+		return Ok(());
+	}
+
+	for item in digest_build_iterator(config, span_begin, span_end) {
+		let child_block = match item {
+			DigestItem::Block(block) => block,
+			DigestItem::Digest { block, .. } => block,
+		};
+		let is_digest = match item {
+			DigestItem::Digest { .. } => true,
+			DigestItem::Block(_) => false,
+		};
+
+		let root = match storage.root(child_block) {
+			Ok(Some(root)) => root,
+			Ok(None) => continue,
+			Err(error) => return Err(error),
+		};
+
+		// the cache is keyed by root, not block number, so a cache hit here
+		// is guaranteed to be this exact child's content - see `DigestBuildCache`.
+		if is_digest {
+			if let Some(keys) = digest_cache.and_then(|digest_cache| digest_cache.get(&root)) {
+				for key in keys {
+					changed_keys.entry(key).or_insert_with(BTreeSet::new).insert(child_block);
+				}
+				continue;
+			}
+		}
+
+		for (key, _) in read_trie_entries(storage, cache, &root)? {
+			changed_keys.entry(key).or_insert_with(BTreeSet::new).insert(child_block);
+		}
+	}
+	Ok(())
+}
+
+/// Reads every `(key, value)` pair out of the trie rooted at `root`.
+///
+/// Consults `cache`'s entries cache first - a cache hit (the exact same
+/// root was already enumerated, e.g. while building a different digest
+/// over an overlapping span) skips the trie walk entirely. A miss walks
+/// the trie once (consulting, and populating, the node cache along the
+/// way) and populates the entries cache for next time.
+///
+/// Fails rather than treating a root whose nodes can't be read (e.g. it was
+/// pruned before this digest was built over it) as "this block changed
+/// nothing" - that would silently produce a wrong digest instead of an
+/// error, which is worse than just not having the digest at all.
+fn read_trie_entries<S: Storage + ?Sized>(
+	storage: &S,
+	cache: Option<&LocalTrieCache>,
+	root: &TrieH256,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+	if let Some(cache) = cache {
+		if let Some(entries) = cache.entries(root) {
+			return Ok(entries);
+		}
+	}
+
+	let eph = CachingEphemeral { storage, cache };
+	let trie = TrieDB::new(&eph, root).map_err(|e| format!("{}", e))?;
+
+	let entries: Vec<(Vec<u8>, Vec<u8>)> = trie.iter().map_err(|e| format!("{}", e))?
+		.map(|entry| entry.map_err(|e| format!("{}", e)))
+		.map(|entry| entry.map(|(key, value)| (untagged_key(&key).to_vec(), value.to_vec())))
+		.collect::<Result<_, String>>()?;
+
+	if let Some(cache) = cache {
+		cache.cache_entries(*root, entries.clone());
+	}
+
+	Ok(entries)
+}
+
+/// Adapts a changes trie `Storage` (lookup-by-hash) into something `TrieDB`
+/// can read, consulting `cache`'s node cache before falling through to
+/// `storage.get` - this is the "`Storage::get` path" the cache short-circuits.
+struct CachingEphemeral<'a, S: 'a + ?Sized> {
+	storage: &'a S,
+	cache: Option<&'a LocalTrieCache<'a>>,
+}
+
+impl<'a, S: 'a + Storage + ?Sized> HashDB for CachingEphemeral<'a, S> {
+	fn get(&self, key: &TrieH256) -> Option<DBValue> {
+		if let Some(cache) = self.cache {
+			if let Some(data) = cache.node(key) {
+				return Some(data);
+			}
+		}
+
+		let data = self.storage.get(key).unwrap_or(None);
+		if let (Some(cache), Some(ref data)) = (self.cache, &data) {
+			cache.cache_node(*key, data.clone());
+		}
+		data
+	}
+
+	fn contains(&self, key: &TrieH256) -> bool {
+		self.get(key).is_some()
+	}
+
+	fn insert(&mut self, _value: &[u8]) -> TrieH256 {
+		unreachable!("CachingEphemeral changes trie storage is read-only")
+	}
+
+	fn emplace(&mut self, _key: TrieH256, _value: DBValue) {
+		unreachable!("CachingEphemeral changes trie storage is read-only")
+	}
+
+	fn remove(&mut self, _key: &TrieH256) {
+		unreachable!("CachingEphemeral changes trie storage is read-only")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config() -> Configuration {
+		Configuration { digest_interval: 4, digest_levels: 2, skewed_digest: None }
+	}
+
+	#[test]
+	fn digest_boundary_finds_the_highest_level_ending_at_block() {
+		let config = config();
+		assert_eq!(digest_boundary(&config, 0), None);
+		assert_eq!(digest_boundary(&config, 3), None);
+		assert_eq!(digest_boundary(&config, 4), Some((4, 4)));
+		assert_eq!(digest_boundary(&config, 8), Some((4, 8)));
+		// block 16 ends both a level-1 (interval 4) and a level-2 (interval 16)
+		// digest - the highest level wins.
+		assert_eq!(digest_boundary(&config, 16), Some((16, 16)));
+	}
+
+	#[test]
+	fn digest_boundary_prefers_a_skewed_digest_ending_at_block() {
+		let mut config = config();
+		config.skewed_digest = Some(::changes_trie::SkewedDigest { begin: 5, end: 6 });
+
+		assert_eq!(digest_boundary(&config, 6), Some((2, 6)));
+		assert_eq!(digest_boundary(&config, 4), Some((4, 4)));
+	}
+}
@@ -0,0 +1,158 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Iterator that decomposes a block range into the digest hierarchy steps
+//! needed to cover it: the highest-level digest blocks that fit wholly
+//! inside the range, falling back to lower digest levels (and finally
+//! individual blocks) for the remainder. `changes_iterator` and `build` both
+//! walk this same decomposition, so a key lookup and a digest construction
+//! agree on exactly which blocks/digests make up a range.
+
+use changes_trie::{Configuration, SkewedDigest};
+
+/// One step while decomposing a block range into digest hierarchy pieces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestItem {
+	/// A `level` digest block, itself built over `digest_interval` blocks,
+	/// ending at (and stored at) block `block`.
+	Digest {
+		/// Digest level (1 = digest-of-blocks, 2 = digest-of-level1-digests, ...).
+		level: u8,
+		/// Number of blocks this digest's span covers.
+		digest_interval: u64,
+		/// The block the digest itself is stored at (the last block of its span).
+		block: u64,
+	},
+	/// A single, non-digested block.
+	Block(u64),
+}
+
+/// Decomposes `[begin, end]` into digest hierarchy steps, preferring the
+/// highest digest level whose span fits wholly within the range at every
+/// position, and falling back to individual blocks where no digest applies.
+///
+/// `config.skewed_digest`, if set, is treated as a first-class (if
+/// irregular) top-level digest rather than being decomposed further,
+/// wherever the decomposition reaches its first block and its whole span
+/// fits within the range.
+pub fn digest_build_iterator(config: &Configuration, begin: u64, end: u64) -> Vec<DigestItem> {
+	if begin > end || config.digest_interval <= 1 || config.digest_levels == 0 {
+		return (begin..=end).map(DigestItem::Block).collect();
+	}
+
+	let mut items = Vec::new();
+	let mut block = begin;
+	while block <= end {
+		if let Some(skewed_item) = skewed_digest_at(config, block, end) {
+			block = match skewed_item {
+				DigestItem::Digest { block: digest_block, .. } => digest_block + 1,
+				DigestItem::Block(block) => block + 1,
+			};
+			items.push(skewed_item);
+			continue;
+		}
+
+		match highest_digest_at(config, block, end) {
+			Some((level, digest_interval, digest_block)) => {
+				items.push(DigestItem::Digest { level, digest_interval, block: digest_block });
+				block = digest_block + 1;
+			},
+			None => {
+				items.push(DigestItem::Block(block));
+				block += 1;
+			},
+		}
+	}
+	items
+}
+
+/// If a skewed digest is configured and its span starts at `block` and ends
+/// at or before `end`, returns it as a `DigestItem::Digest` with its real
+/// (possibly irregular) span recorded as `digest_interval`.
+fn skewed_digest_at(config: &Configuration, block: u64, end: u64) -> Option<DigestItem> {
+	let skewed: SkewedDigest = config.skewed_digest?;
+	if skewed.begin != block || skewed.end > end || skewed.end < skewed.begin {
+		return None;
+	}
+
+	Some(DigestItem::Digest {
+		level: config.digest_levels,
+		digest_interval: skewed.end - skewed.begin + 1,
+		block: skewed.end,
+	})
+}
+
+/// Finds the highest configured digest level whose span starts exactly at
+/// `block` and ends at or before `end`.
+fn highest_digest_at(config: &Configuration, block: u64, end: u64) -> Option<(u8, u64, u64)> {
+	(1..=config.digest_levels).rev().filter_map(|level| {
+		let digest_interval = config.digest_interval.checked_pow(level as u32)?;
+		let block_index = block.checked_sub(1)?;
+		if digest_interval == 0 || block_index % digest_interval != 0 {
+			return None;
+		}
+
+		let digest_block = block + digest_interval - 1;
+		if digest_block > end {
+			return None;
+		}
+
+		Some((level, digest_interval, digest_block))
+	}).next()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use changes_trie::Configuration;
+
+	fn config() -> Configuration {
+		Configuration { digest_interval: 4, digest_levels: 2, skewed_digest: None }
+	}
+
+	#[test]
+	fn digest_build_iterator_does_not_panic_on_genesis_range() {
+		// block 0 (genesis) as a range start is an entirely ordinary input on
+		// the public key_changes/key_changes_proof entry points - this must
+		// not underflow/panic even though 0 can never itself be a digest start.
+		let items = digest_build_iterator(&config(), 0, 4);
+		assert_eq!(items, vec![DigestItem::Block(0), DigestItem::Digest {
+			level: 1, digest_interval: 4, block: 4,
+		}]);
+	}
+
+	#[test]
+	fn skewed_digest_is_treated_as_a_first_class_top_level_digest() {
+		let mut config = config();
+		config.skewed_digest = Some(SkewedDigest { begin: 5, end: 6 });
+
+		// the skewed digest's irregular span is taken as-is rather than
+		// being decomposed into regular-interval pieces.
+		let items = digest_build_iterator(&config, 5, 6);
+		assert_eq!(items, vec![DigestItem::Digest { level: 2, digest_interval: 2, block: 6 }]);
+	}
+
+	#[test]
+	fn skewed_digest_is_ignored_outside_its_own_span() {
+		let mut config = config();
+		config.skewed_digest = Some(SkewedDigest { begin: 5, end: 6 });
+
+		// the skewed digest only applies where the decomposition reaches
+		// its first block - elsewhere, the regular hierarchy decides.
+		let items = digest_build_iterator(&config, 1, 3);
+		assert_eq!(items, vec![DigestItem::Block(1), DigestItem::Block(2), DigestItem::Block(3)]);
+	}
+}
@@ -0,0 +1,195 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Shared trie node/value cache for changes trie computation.
+//!
+//! Building a changes trie - especially a digest, which re-reads every
+//! block/digest in the period it covers - ends up re-fetching and
+//! re-decoding the same handful of trie nodes over and over. `TrieCache`
+//! lets a caller share that work across many calls to
+//! `compute_changes_trie_root` by keeping a cache alive across blocks.
+//!
+//! The entries cache is partitioned per storage root rather than shared
+//! across roots. This is essential: the same storage key can map to a
+//! different value under the main trie than under a child or changes trie
+//! that shares this backend, so a single global cache keyed on content
+//! alone would silently return the wrong entries whenever more than one
+//! root is in play.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use trie_backend::{DBValue, TrieH256};
+
+/// The full `(key, value)` set of a trie rooted at a given `TrieH256`, as
+/// produced by enumerating it once - see `TrieCache::entries`.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Shared trie node/entries cache. Cheap to keep alive (and pass around as
+/// an `Arc`) across many blocks' worth of changes trie computation.
+#[derive(Default)]
+pub struct TrieCache {
+	shared: RwLock<SharedCacheData>,
+}
+
+#[derive(Default)]
+struct SharedCacheData {
+	nodes: HashMap<TrieH256, DBValue>,
+	entries: HashMap<TrieH256, Entries>,
+}
+
+impl TrieCache {
+	/// Creates a new, empty cache.
+	pub fn new() -> Self {
+		TrieCache::default()
+	}
+
+	/// Looks up a cached trie node's raw, encoded data.
+	pub fn node(&self, hash: &TrieH256) -> Option<DBValue> {
+		self.shared.read().expect("TrieCache lock is not poisoned").nodes.get(hash).cloned()
+	}
+
+	/// Looks up the full, already-enumerated `(key, value)` set of the trie
+	/// rooted at `root`.
+	pub fn entries(&self, root: &TrieH256) -> Option<Entries> {
+		self.shared.read().expect("TrieCache lock is not poisoned").entries.get(root).cloned()
+	}
+
+	/// Opens a local, uncommitted view onto this cache.
+	pub fn local(&self) -> LocalTrieCache {
+		LocalTrieCache {
+			shared: self,
+			nodes: RefCell::new(HashMap::new()),
+			entries: RefCell::new(HashMap::new()),
+		}
+	}
+}
+
+/// A `ReadOnce`-style local layer on top of a shared `TrieCache`: reads
+/// check the local layer first and fall through to the shared cache, while
+/// writes only accumulate locally. Call `commit` once the computation that
+/// produced them has succeeded to promote them into the shared cache -
+/// an abandoned computation can just drop its `LocalTrieCache` instead,
+/// leaving the shared cache untouched by speculative reads.
+pub struct LocalTrieCache<'a> {
+	shared: &'a TrieCache,
+	nodes: RefCell<HashMap<TrieH256, DBValue>>,
+	entries: RefCell<HashMap<TrieH256, Entries>>,
+}
+
+impl<'a> LocalTrieCache<'a> {
+	/// Looks up a node, local layer first.
+	pub fn node(&self, hash: &TrieH256) -> Option<DBValue> {
+		if let Some(data) = self.nodes.borrow().get(hash).cloned() {
+			return Some(data);
+		}
+		self.shared.node(hash)
+	}
+
+	/// Records a freshly-fetched node in the local layer.
+	pub fn cache_node(&self, hash: TrieH256, data: DBValue) {
+		self.nodes.borrow_mut().insert(hash, data);
+	}
+
+	/// Looks up the full entry set of the trie rooted at `root`, local layer first.
+	pub fn entries(&self, root: &TrieH256) -> Option<Entries> {
+		if let Some(entries) = self.entries.borrow().get(root).cloned() {
+			return Some(entries);
+		}
+		self.shared.entries(root)
+	}
+
+	/// Records a freshly-enumerated entry set for `root` in the local layer.
+	pub fn cache_entries(&self, root: TrieH256, entries: Entries) {
+		self.entries.borrow_mut().insert(root, entries);
+	}
+
+	/// Promotes every locally-cached entry into the shared cache.
+	pub fn commit(self) {
+		let mut shared = self.shared.shared.write().expect("TrieCache lock is not poisoned");
+		shared.nodes.extend(self.nodes.into_inner());
+		shared.entries.extend(self.entries.into_inner());
+	}
+}
+
+/// Caches the set of storage keys a just-built digest was found to contain,
+/// keyed by the digest's own trie root rather than its block number.
+///
+/// Keying by root (not block number) matters for exactly the reason the
+/// value cache above is partitioned per root: a block number can end up
+/// with different digest content across a speculative/trial execution (e.g.
+/// during fork-choice) that's later redone with different changes. Since a
+/// root is content-addressed, a stale entry from a discarded computation
+/// simply never matches the root a later, different computation produces -
+/// keying by block number would instead silently serve the stale keys.
+///
+/// Lets building a level-L digest reuse its level-(L-1) digests' already
+/// computed key sets instead of re-scanning every block/digest in its span,
+/// turning the per-digest work from O(period length) into O(number of
+/// sub-digests).
+#[derive(Default)]
+pub struct DigestBuildCache {
+	digests: RwLock<HashMap<TrieH256, Vec<Vec<u8>>>>,
+}
+
+impl DigestBuildCache {
+	/// Creates a new, empty digest build cache.
+	pub fn new() -> Self {
+		DigestBuildCache::default()
+	}
+
+	/// Returns the cached set of keys for the digest rooted at `root`, if any.
+	pub fn get(&self, root: &TrieH256) -> Option<Vec<Vec<u8>>> {
+		self.digests.read().expect("DigestBuildCache lock is not poisoned").get(root).cloned()
+	}
+
+	/// Records the set of keys found while building the digest rooted at `root`.
+	pub fn insert(&self, root: TrieH256, keys: Vec<Vec<u8>>) {
+		self.digests.write().expect("DigestBuildCache lock is not poisoned").insert(root, keys);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn local_entries_cache_hit_avoids_the_shared_cache_until_commit() {
+		let shared = TrieCache::new();
+		let root = TrieH256::from([1; 32]);
+		let entries = vec![(b"key".to_vec(), b"value".to_vec())];
+
+		let local = shared.local();
+		assert_eq!(local.entries(&root), None);
+		local.cache_entries(root, entries.clone());
+		assert_eq!(local.entries(&root), Some(entries.clone()));
+
+		// nothing is promoted into the shared cache until `commit`.
+		assert_eq!(shared.entries(&root), None);
+		local.commit();
+		assert_eq!(shared.entries(&root), Some(entries));
+	}
+
+	#[test]
+	fn digest_build_cache_round_trips_by_root() {
+		let cache = DigestBuildCache::new();
+		let root = TrieH256::from([2; 32]);
+		assert_eq!(cache.get(&root), None);
+
+		cache.insert(root, vec![b"key".to_vec()]);
+		assert_eq!(cache.get(&root), Some(vec![b"key".to_vec()]));
+	}
+}
@@ -0,0 +1,485 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Enumerates the blocks in which a storage key changed, skipping over
+//! ranges the digest hierarchy already proves were untouched, and lets a
+//! light client prove that enumeration to someone who only trusts a set of
+//! block headers (and, through them, changes-trie roots).
+
+use std::collections::{HashMap, HashSet};
+use hashdb::{HashDB, DBValue};
+use memorydb::MemoryDB;
+use patricia_trie::{TrieDB, Trie, Recorder};
+use trie_backend::TrieH256;
+use changes_trie::{Configuration, Storage};
+use changes_trie::build_iterator::{digest_build_iterator, DigestItem};
+use changes_trie::input::{InputKeyKind, encode_u64_list, trie_key};
+
+/// Error enumerating, proving or checking changes-trie key changes.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+	/// Trie lookup error.
+	Trie(String),
+	/// No changes trie root is known for the given block.
+	MissingRoot(u64),
+	/// The proof doesn't contain a node needed to resolve a lookup.
+	MissingNode,
+	/// The nodes the proof provides for a block don't hash to the root
+	/// that's trusted for that block.
+	RootMismatch(u64),
+	/// A digest pointed outside of the range being proved.
+	BlockOutOfRange(u64),
+}
+
+impl ::std::fmt::Display for Error {
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+		match *self {
+			Error::Trie(ref e) => write!(f, "changes trie error: {}", e),
+			Error::MissingRoot(block) => write!(f, "missing changes trie root for block {}", block),
+			Error::MissingNode => write!(f, "proof is missing a required changes trie node"),
+			Error::RootMismatch(block) => write!(f, "changes trie proof doesn't match trusted root at block {}", block),
+			Error::BlockOutOfRange(block) => write!(f, "digest points to block {} outside of the proved range", block),
+		}
+	}
+}
+
+/// Returns the sorted set of blocks in `[begin, end]` in which `key`'s value
+/// changed, using the digest hierarchy to avoid reading every block's
+/// individual changes trie.
+pub fn key_changes<S: Storage>(
+	config: &Configuration,
+	storage: &S,
+	begin: u64,
+	end: u64,
+	key: &[u8],
+) -> Result<Vec<u64>, String> {
+	let mut changes = HashSet::new();
+	for item in digest_build_iterator(config, begin, end) {
+		match item {
+			DigestItem::Block(block) => {
+				let root = require_root(storage, block)?;
+				if read_at_root(storage, &root, InputKeyKind::ExtrinsicIndex, key).map_err(|e| format!("{}", e))?.is_some() {
+					changes.insert(block);
+				}
+			},
+			DigestItem::Digest { digest_interval, block, .. } => {
+				changes.extend(
+					digest_changes(storage, config, digest_interval, block, key).map_err(|e| format!("{}", e))?
+				);
+			},
+		}
+	}
+
+	let mut changes: Vec<_> = changes.into_iter().collect();
+	changes.sort();
+	Ok(changes)
+}
+
+/// Resolves a digest block's entries into the set of individual blocks it
+/// ultimately points at. An entry is a final block number unless it's the
+/// block a lower-level digest covering it is itself stored at, in which
+/// case it's recursed into - found by recomputing the digest's own span
+/// with `digest_build_iterator`, exactly like `build` did while
+/// constructing it. This is required (rather than just checking `level`)
+/// because a digest's span can decompose into a heterogeneous mix of
+/// blocks and lower-level digests - e.g. a level-2 digest's trailing
+/// blocks after its last level-1 digest, or a skewed digest's span.
+fn digest_changes<S: Storage>(
+	storage: &S,
+	config: &Configuration,
+	digest_interval: u64,
+	digest_block: u64,
+	key: &[u8],
+) -> Result<Vec<u64>, Error> {
+	let root = require_root(storage, digest_block)?;
+	let entries = match read_at_root(storage, &root, InputKeyKind::DigestIndex, key)? {
+		Some(entries) => entries,
+		None => return Ok(Vec::new()),
+	};
+
+	let children = child_items(config, digest_interval, digest_block);
+	let mut result = Vec::new();
+	for entry_block in entries {
+		match children.get(&entry_block) {
+			Some(&DigestItem::Digest { digest_interval: child_interval, block: child_block, .. }) =>
+				result.extend(digest_changes(storage, config, child_interval, child_block, key)?),
+			_ => result.push(entry_block),
+		}
+	}
+	Ok(result)
+}
+
+/// Recomputes the digest hierarchy steps making up the span a `block`
+/// digest built `digest_interval` blocks out of, indexed by each step's
+/// own block number - so a raw entry read out of the digest's trie can be
+/// resolved to the kind of thing it actually points at.
+fn child_items(config: &Configuration, digest_interval: u64, digest_block: u64) -> HashMap<u64, DigestItem> {
+	let span_begin = digest_block + 1 - digest_interval;
+	let span_end = digest_block - 1;
+	digest_build_iterator(config, span_begin, span_end).into_iter()
+		.map(|item| {
+			let block = match item {
+				DigestItem::Block(block) => block,
+				DigestItem::Digest { block, .. } => block,
+			};
+			(block, item)
+		})
+		.collect()
+}
+
+/// Produces a proof that `key_changes(config, _, begin, end, key)` would
+/// return the blocks it does, by recording every changes-trie node visited
+/// while walking the same digest hierarchy against a real backend.
+pub fn key_changes_proof<S: Storage>(
+	storage: &S,
+	config: &Configuration,
+	begin: u64,
+	end: u64,
+	key: &[u8],
+) -> Result<Vec<Vec<u8>>, String> {
+	let mut proof_nodes = HashSet::new();
+	for item in digest_build_iterator(config, begin, end) {
+		match item {
+			DigestItem::Block(block) => {
+				record_lookup(storage, block, InputKeyKind::ExtrinsicIndex, key, &mut proof_nodes)
+					.map_err(|e| format!("{}", e))?;
+			},
+			DigestItem::Digest { digest_interval, block, .. } => {
+				record_digest(storage, config, digest_interval, block, key, &mut proof_nodes).map_err(|e| format!("{}", e))?;
+			},
+		}
+	}
+
+	Ok(proof_nodes.into_iter().collect())
+}
+
+fn record_digest<S: Storage>(
+	storage: &S,
+	config: &Configuration,
+	digest_interval: u64,
+	digest_block: u64,
+	key: &[u8],
+	proof_nodes: &mut HashSet<Vec<u8>>,
+) -> Result<(), Error> {
+	let entries = record_lookup(storage, digest_block, InputKeyKind::DigestIndex, key, proof_nodes)?;
+	let entries = match entries {
+		Some(entries) => entries,
+		None => return Ok(()),
+	};
+
+	let children = child_items(config, digest_interval, digest_block);
+	for entry_block in entries {
+		if let Some(&DigestItem::Digest { digest_interval: child_interval, block: child_block, .. }) = children.get(&entry_block) {
+			record_digest(storage, config, child_interval, child_block, key, proof_nodes)?;
+		}
+	}
+	Ok(())
+}
+
+fn record_lookup<S: Storage>(
+	storage: &S,
+	block: u64,
+	kind: InputKeyKind,
+	key: &[u8],
+	proof_nodes: &mut HashSet<Vec<u8>>,
+) -> Result<Option<Vec<u64>>, Error> {
+	let root = require_root(storage, block)?;
+	let eph = Ephemeral { storage };
+	let mut recorder = Recorder::new();
+	let value = {
+		let trie = TrieDB::new(&eph, &root).map_err(|e| Error::Trie(format!("{}", e)))?;
+		trie.get_with(&trie_key(kind, key), &mut recorder).map_err(|e| Error::Trie(format!("{}", e)))?
+	};
+
+	proof_nodes.extend(recorder.drain().into_iter().map(|record| record.data));
+	Ok(value.map(|v| decode_block_list(&v)))
+}
+
+/// Something that can hand out a *trusted* changes-trie root for a block
+/// number - typically backed by already-verified block headers.
+pub trait RootsStorage: Send + Sync {
+	/// Get the trusted changes trie root for `block`, if known.
+	fn root(&self, block: u64) -> Result<Option<TrieH256>, String>;
+}
+
+impl RootsStorage for HashMap<u64, TrieH256> {
+	fn root(&self, block: u64) -> Result<Option<TrieH256>, String> {
+		Ok(self.get(&block).cloned())
+	}
+}
+
+/// Checks a proof produced by `key_changes_proof`, re-walking the same
+/// digest hierarchy using only the recorded nodes and `roots_provider`'s
+/// independently trusted roots. Every digest level is anchored to its own
+/// trusted root, a digest is never followed outside `[begin, end]`, and a
+/// missing node is a hard failure - a prover cannot simply omit a block.
+pub fn key_changes_proof_check<R: RootsStorage>(
+	config: &Configuration,
+	roots_provider: &R,
+	proof: Vec<Vec<u8>>,
+	begin: u64,
+	end: u64,
+	key: &[u8],
+) -> Result<Vec<u64>, String> {
+	let mut db = MemoryDB::new();
+	for node in proof {
+		db.insert(&node);
+	}
+
+	let mut changes = HashSet::new();
+	for item in digest_build_iterator(config, begin, end) {
+		match item {
+			DigestItem::Block(block) => {
+				if checked_lookup(&db, roots_provider, block, InputKeyKind::ExtrinsicIndex, key)
+					.map_err(|e| format!("{}", e))?.is_some() {
+					changes.insert(block);
+				}
+			},
+			DigestItem::Digest { digest_interval, block, .. } => {
+				let found = checked_digest(&db, roots_provider, config, digest_interval, block, begin, end, key)
+					.map_err(|e| format!("{}", e))?;
+				changes.extend(found);
+			},
+		}
+	}
+
+	let mut changes: Vec<_> = changes.into_iter().collect();
+	changes.sort();
+	Ok(changes)
+}
+
+fn checked_digest<R: RootsStorage>(
+	db: &MemoryDB,
+	roots_provider: &R,
+	config: &Configuration,
+	digest_interval: u64,
+	digest_block: u64,
+	begin: u64,
+	end: u64,
+	key: &[u8],
+) -> Result<Vec<u64>, Error> {
+	let entries = match checked_lookup(db, roots_provider, digest_block, InputKeyKind::DigestIndex, key)? {
+		Some(entries) => entries,
+		None => return Ok(Vec::new()),
+	};
+
+	let children = child_items(config, digest_interval, digest_block);
+	let mut result = Vec::new();
+	for pointed_block in entries {
+		if pointed_block < begin || pointed_block > end {
+			return Err(Error::BlockOutOfRange(pointed_block));
+		}
+
+		match children.get(&pointed_block) {
+			Some(&DigestItem::Digest { digest_interval: child_interval, block: child_block, .. }) =>
+				result.extend(checked_digest(db, roots_provider, config, child_interval, child_block, begin, end, key)?),
+			_ => result.push(pointed_block),
+		}
+	}
+	Ok(result)
+}
+
+fn checked_lookup<R: RootsStorage>(
+	db: &MemoryDB,
+	roots_provider: &R,
+	block: u64,
+	kind: InputKeyKind,
+	key: &[u8],
+) -> Result<Option<Vec<u64>>, Error> {
+	let root = roots_provider.root(block).map_err(Error::Trie)?.ok_or(Error::MissingRoot(block))?;
+	if !db.contains(&root) {
+		return Err(Error::RootMismatch(block));
+	}
+
+	let trie = TrieDB::new(db, &root).map_err(|e| Error::Trie(format!("{}", e)))?;
+	let value = trie.get(&trie_key(kind, key)).map_err(|_| Error::MissingNode)?;
+	Ok(value.map(|v| decode_block_list(&v)))
+}
+
+fn require_root<S: Storage>(storage: &S, block: u64) -> Result<TrieH256, Error> {
+	storage.root(block).map_err(Error::Trie)?.ok_or(Error::MissingRoot(block))
+}
+
+fn read_at_root<S: Storage>(
+	storage: &S,
+	root: &TrieH256,
+	kind: InputKeyKind,
+	key: &[u8],
+) -> Result<Option<Vec<u64>>, Error> {
+	let eph = Ephemeral { storage };
+	let trie = TrieDB::new(&eph, root).map_err(|e| Error::Trie(format!("{}", e)))?;
+	let value = trie.get(&trie_key(kind, key)).map_err(|e| Error::Trie(format!("{}", e)))?;
+	Ok(value.map(|v| decode_block_list(&v)))
+}
+
+/// Decodes a changes-trie entry value into the list of block/digest numbers
+/// it packs (big-endian `u64`s, back to back - the same encoding used
+/// everywhere else in the changes trie).
+fn decode_block_list(raw: &[u8]) -> Vec<u64> {
+	raw.chunks(8)
+		.map(|chunk| chunk.iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64))
+		.collect()
+}
+
+/// Adapts a changes-trie `Storage` (lookup-by-hash) so it can be read
+/// directly by `TrieDB`, without first copying every node into a `MemoryDB`.
+struct Ephemeral<'a, S: 'a> {
+	storage: &'a S,
+}
+
+impl<'a, S: 'a + Storage> HashDB for Ephemeral<'a, S> {
+	fn get(&self, key: &TrieH256) -> Option<DBValue> {
+		self.storage.get(key).unwrap_or(None)
+	}
+
+	fn contains(&self, key: &TrieH256) -> bool {
+		self.get(key).is_some()
+	}
+
+	fn insert(&mut self, _value: &[u8]) -> TrieH256 {
+		unreachable!("Ephemeral changes trie storage is read-only")
+	}
+
+	fn emplace(&mut self, _key: TrieH256, _value: DBValue) {
+		unreachable!("Ephemeral changes trie storage is read-only")
+	}
+
+	fn remove(&mut self, _key: &TrieH256) {
+		unreachable!("Ephemeral changes trie storage is read-only")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use patricia_trie::{TrieDBMut, TrieMut};
+	use changes_trie::InMemoryStorage;
+
+	fn no_digests() -> Configuration {
+		Configuration { digest_interval: 0, digest_levels: 0, skewed_digest: None }
+	}
+
+	/// Builds a one-block changes trie containing `{ key => [0u64] }` and
+	/// stores it at `block`.
+	fn insert_block(storage: &InMemoryStorage, block: u64, key: &[u8]) -> TrieH256 {
+		let mut db = MemoryDB::new();
+		let mut root = TrieH256::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			trie.insert(&trie_key(InputKeyKind::ExtrinsicIndex, key), &0u64.to_be_bytes())
+				.expect("inserting into a fresh trie never fails");
+		}
+
+		let nodes = db.drain().into_iter().map(|(hash, (value, _rc))| (hash, value)).collect();
+		storage.insert(block, root, nodes);
+		root
+	}
+
+	fn multi_level_config() -> Configuration {
+		Configuration { digest_interval: 4, digest_levels: 2, skewed_digest: None }
+	}
+
+	/// Builds a digest's changes trie containing `{ key => children }` and
+	/// stores it at `block`.
+	fn insert_digest_block(storage: &InMemoryStorage, block: u64, key: &[u8], children: &[u64]) -> TrieH256 {
+		let mut db = MemoryDB::new();
+		let mut root = TrieH256::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			trie.insert(&trie_key(InputKeyKind::DigestIndex, key), &encode_u64_list(children))
+				.expect("inserting into a fresh trie never fails");
+		}
+
+		let nodes = db.drain().into_iter().map(|(hash, (value, _rc))| (hash, value)).collect();
+		storage.insert(block, root, nodes);
+		root
+	}
+
+	#[test]
+	fn key_changes_handles_a_two_level_digest_hierarchy() {
+		let config = multi_level_config();
+		let storage = InMemoryStorage::new();
+		// level-2 digest at block 16 spans [1, 15], decomposing into
+		// level-1 digests at 4/8/12 plus the bare trailing blocks 13-15 -
+		// a key changed both inside a child digest's span and in one of
+		// those trailing blocks exercises both branches of `digest_changes`.
+		insert_block(&storage, 1, b"key");
+		insert_digest_block(&storage, 4, b"key", &[1]);
+		insert_block(&storage, 13, b"key");
+		insert_digest_block(&storage, 16, b"key", &[4, 13]);
+
+		let changes = key_changes(&config, &storage, 1, 16, b"key").expect("key_changes succeeds");
+		assert_eq!(changes, vec![1, 13]);
+	}
+
+	#[test]
+	fn key_changes_proof_roundtrips_through_a_two_level_digest_hierarchy() {
+		let config = multi_level_config();
+		let storage = InMemoryStorage::new();
+		insert_block(&storage, 1, b"key");
+		insert_digest_block(&storage, 4, b"key", &[1]);
+		insert_block(&storage, 13, b"key");
+		insert_digest_block(&storage, 16, b"key", &[4, 13]);
+
+		let changes = key_changes(&config, &storage, 1, 16, b"key").expect("key_changes succeeds");
+		let proof = key_changes_proof(&storage, &config, 1, 16, b"key").expect("proving succeeds");
+
+		let mut roots = HashMap::new();
+		for block in [1u64, 4, 16].iter() {
+			roots.insert(*block, storage.root(*block).unwrap().unwrap());
+		}
+
+		let checked = key_changes_proof_check(&config, &roots, proof, 1, 16, b"key").expect("checking succeeds");
+		assert_eq!(checked, changes);
+	}
+
+	#[test]
+	fn key_changes_proof_roundtrips_through_key_changes_proof_check() {
+		let config = no_digests();
+		let storage = InMemoryStorage::new();
+		insert_block(&storage, 1, b"key");
+		insert_block(&storage, 2, b"other");
+		insert_block(&storage, 3, b"key");
+
+		let changes = key_changes(&config, &storage, 1, 3, b"key").expect("key_changes succeeds");
+		assert_eq!(changes, vec![1, 3]);
+
+		let proof = key_changes_proof(&storage, &config, 1, 3, b"key").expect("proving succeeds");
+
+		let mut roots = HashMap::new();
+		for block in 1..=3u64 {
+			roots.insert(block, storage.root(block).unwrap().unwrap());
+		}
+
+		let checked = key_changes_proof_check(&config, &roots, proof, 1, 3, b"key").expect("checking succeeds");
+		assert_eq!(checked, changes);
+	}
+
+	#[test]
+	fn key_changes_proof_check_rejects_a_truncated_proof() {
+		let config = no_digests();
+		let storage = InMemoryStorage::new();
+		insert_block(&storage, 1, b"key");
+
+		let mut proof = key_changes_proof(&storage, &config, 1, 1, b"key").expect("proving succeeds");
+		proof.pop();
+
+		let mut roots = HashMap::new();
+		roots.insert(1u64, storage.root(1).unwrap().unwrap());
+
+		assert!(key_changes_proof_check(&config, &roots, proof, 1, 1, b"key").is_err());
+	}
+}
@@ -0,0 +1,104 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Changes trie input pairs - the data `build::prepare_input` assembles,
+//! before it's flattened into the raw `(key, value)` leaves a trie is built
+//! from.
+
+/// A single changes trie input pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputPair {
+	/// `{ storage key => extrinsics }`, for a regular (non-digest) block:
+	/// the extrinsics that changed `storage key` in that block.
+	ExtrinsicIndex(Vec<u8>, Vec<u32>),
+	/// `{ storage key => blocks|digest blocks }`, for a digest block: the
+	/// level-1 digest stores the actual blocks `storage key` changed in,
+	/// higher-level digests store the lower-level digest blocks to recurse
+	/// into, plus any blocks of their span no lower-level digest covers.
+	DigestIndex(Vec<u8>, Vec<u64>),
+}
+
+/// Distinguishes an `ExtrinsicIndex` entry's trie key from a `DigestIndex`
+/// entry's trie key for the *same* storage key - a digest-boundary block
+/// commits both (one for its own direct changes, one for the span it
+/// digests), so a plain, untagged storage key can't be used for both
+/// without one silently clobbering the other in the trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKeyKind {
+	/// Tags an `ExtrinsicIndex` entry's key.
+	ExtrinsicIndex,
+	/// Tags a `DigestIndex` entry's key.
+	DigestIndex,
+}
+
+impl InputKeyKind {
+	fn tag(self) -> u8 {
+		match self {
+			InputKeyKind::ExtrinsicIndex => 0,
+			InputKeyKind::DigestIndex => 1,
+		}
+	}
+}
+
+/// Builds the trie key a storage `key`'s entry of `kind` is stored/looked-up
+/// under. `untagged_key` is the inverse.
+pub fn trie_key(kind: InputKeyKind, key: &[u8]) -> Vec<u8> {
+	let mut tagged = Vec::with_capacity(key.len() + 1);
+	tagged.push(kind.tag());
+	tagged.extend_from_slice(key);
+	tagged
+}
+
+/// Strips the tag `trie_key` prepends, recovering the original storage key
+/// regardless of which kind of entry it tagged.
+pub fn untagged_key(tagged: &[u8]) -> &[u8] {
+	&tagged[1..]
+}
+
+impl Into<(Vec<u8>, Vec<u8>)> for InputPair {
+	fn into(self) -> (Vec<u8>, Vec<u8>) {
+		match self {
+			InputPair::ExtrinsicIndex(key, extrinsics) =>
+				(trie_key(InputKeyKind::ExtrinsicIndex, &key), encode_u32_list(&extrinsics)),
+			InputPair::DigestIndex(key, blocks) =>
+				(trie_key(InputKeyKind::DigestIndex, &key), encode_u64_list(&blocks)),
+		}
+	}
+}
+
+/// Encodes a list of block/digest-block numbers as big-endian `u64`s, back
+/// to back. `changes_iterator::decode_block_list` is the inverse.
+pub fn encode_u64_list(numbers: &[u64]) -> Vec<u8> {
+	let mut encoded = Vec::with_capacity(numbers.len() * 8);
+	for number in numbers {
+		encoded.extend_from_slice(&[
+			(number >> 56) as u8, (number >> 48) as u8, (number >> 40) as u8, (number >> 32) as u8,
+			(number >> 24) as u8, (number >> 16) as u8, (number >> 8) as u8, *number as u8,
+		]);
+	}
+	encoded
+}
+
+/// Encodes a list of extrinsic indices as big-endian `u32`s, back to back.
+pub fn encode_u32_list(numbers: &[u32]) -> Vec<u8> {
+	let mut encoded = Vec::with_capacity(numbers.len() * 4);
+	for number in numbers {
+		encoded.extend_from_slice(&[
+			(number >> 24) as u8, (number >> 16) as u8, (number >> 8) as u8, *number as u8,
+		]);
+	}
+	encoded
+}
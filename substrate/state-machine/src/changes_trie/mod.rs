@@ -34,15 +34,18 @@
 
 mod build;
 mod build_iterator;
+mod cache;
 mod changes_iterator;
 mod input;
 mod storage;
 
-pub use self::storage::InMemoryStorage;
+pub use self::cache::{DigestBuildCache, TrieCache};
+pub use self::storage::{InMemoryStorage, prune};
 
 use std::sync::Arc;
 use trie_backend::{DBValue, TrieH256};
 use changes_trie::build::prepare_input;
+use changes_trie::input::InputPair;
 use overlayed_changes::OverlayedChanges;
 use {Storage as TrieStorage};
 
@@ -53,6 +56,20 @@ pub trait Storage: Send + Sync {
 
 	/// Get a trie node.
 	fn get(&self, key: &TrieH256) -> Result<Option<DBValue>, String>;
+
+	/// Enumerate every trie node reachable from `root`'s sub-trie. Used by
+	/// `prune` to find the nodes a discarded changes trie (or digest) would
+	/// otherwise leak. Storages that don't support pruning can leave this
+	/// returning an empty list.
+	fn trie_nodes_for_root(&self, _root: &TrieH256) -> Result<Vec<TrieH256>, String> {
+		Ok(Vec::new())
+	}
+
+	/// Decrement a trie node's reference count, physically removing it once
+	/// the count reaches zero. No-op for storages that don't support pruning.
+	fn remove_trie_node(&self, _node: &TrieH256) -> Result<(), String> {
+		Ok(())
+	}
 }
 
 /// Changes trie configuration.
@@ -65,18 +82,155 @@ pub struct Configuration {
 	/// created at all (even level1 digests). 1 means only level1-digests are created.
 	/// 2 means that every digest_interval^2 there will be a level2-digest, and so on.
 	pub digest_levels: u8,
+	/// A skewed digest to build instead of (or in addition to) the regular
+	/// hierarchy: covers the actual blocks elapsed since the last regular
+	/// digest boundary, rather than requiring a full `digest_interval`
+	/// boundary. Set when a configuration change or chain halt lands
+	/// mid-period, so that period's changes are still provable.
+	pub skewed_digest: Option<SkewedDigest>,
+}
+
+/// An irregular-length digest, covering `[begin, end]` rather than a full,
+/// `digest_interval`-aligned span. `build_iterator`/`changes_iterator`
+/// treat it as a first-class digest block, stored at (and covering up to)
+/// `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkewedDigest {
+	/// First block the skewed digest covers.
+	pub begin: u64,
+	/// Last block the skewed digest covers, and the block it's stored at.
+	pub end: u64,
 }
 
 /// Compute the changes trie root and transaction for given block.
 /// Returns None if there's no data to perform computation.
-pub fn compute_changes_trie_root(storage: Option<Arc<Storage>>, changes: &OverlayedChanges)
-	-> Option<([u8; 32], Vec<(Vec<u8>, Vec<u8>)>)>
-{
-	let input_pairs = prepare_input(storage, changes)?;
+///
+/// Fails if building a digest required reading a block/digest whose trie
+/// can no longer be opened - see `build::read_trie_entries`.
+///
+/// `cache`, if given, is consulted (and populated) while reading the
+/// backend to build any digest this block requires - see `TrieCache` for
+/// why it's partitioned per storage root. Passing `None` just means every
+/// read goes straight to `storage`.
+///
+/// `digest_cache`, if given, lets building a higher-level digest reuse the
+/// keys a lower-level digest was already found to contain, instead of
+/// re-scanning every block/digest in its span - see `DigestBuildCache`.
+/// Every key this block's trie holds an entry for is recorded into it under
+/// the block's root for future reuse, whether or not the block is a digest.
+pub fn compute_changes_trie_root(
+	storage: Option<Arc<Storage>>,
+	config: &Configuration,
+	block: u64,
+	changes: &OverlayedChanges,
+	cache: Option<&TrieCache>,
+	digest_cache: Option<&DigestBuildCache>,
+) -> Result<Option<([u8; 32], Vec<(Vec<u8>, Vec<u8>)>)>, String> {
+	let local_cache = cache.map(|cache| cache.local());
+	let input_pairs = match prepare_input(storage, config, changes, block, local_cache.as_ref(), digest_cache)? {
+		Some(input_pairs) => input_pairs,
+		None => return Ok(None),
+	};
+
+	// every key this block's trie contains an entry for, regardless of
+	// `InputPair` kind - a cache hit must return the exact same set a full
+	// trie walk (`build::read_trie_entries`) would have found on a miss.
+	let digest_keys: Vec<_> = input_pairs.iter()
+		.map(|pair| match *pair {
+			InputPair::ExtrinsicIndex(ref key, _) => key.clone(),
+			InputPair::DigestIndex(ref key, _) => key.clone(),
+		})
+		.collect::<::std::collections::BTreeSet<_>>()
+		.into_iter()
+		.collect();
+
 	let transaction = input_pairs.into_iter()
 		.map(Into::into)
 		.collect::<Vec<_>>();
 	let root = ::triehash::trie_root(transaction.iter().map(|(k, v)| (&*k, &*v))).0;
 
-	Some((root, transaction))
+	// keyed by the root we just computed, not `block` - see `DigestBuildCache`
+	// for why that distinction matters.
+	if let Some(digest_cache) = digest_cache {
+		if !digest_keys.is_empty() {
+			digest_cache.insert(TrieH256::from(root), digest_keys);
+		}
+	}
+
+	if let Some(local_cache) = local_cache {
+		local_cache.commit();
+	}
+
+	Ok(Some((root, transaction)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use patricia_trie::{TrieDBMut, TrieMut};
+	use changes_trie::changes_iterator::key_changes;
+
+	fn config() -> Configuration {
+		Configuration { digest_interval: 4, digest_levels: 1, skewed_digest: None }
+	}
+
+	fn changes_for(key: &[u8]) -> OverlayedChanges {
+		let mut changes = OverlayedChanges::default();
+		changes.set_extrinsic_index(0);
+		changes.set_storage(key.to_vec(), Some(b"value".to_vec()));
+		changes
+	}
+
+	/// Builds the real trie `compute_changes_trie_root` computed a flat
+	/// `transaction` for, and stores it so later blocks' digests can read it
+	/// back - mirroring what a caller committing the transaction would do.
+	fn store_transaction(storage: &InMemoryStorage, block: u64, transaction: Vec<(Vec<u8>, Vec<u8>)>) -> TrieH256 {
+		let mut db = ::memorydb::MemoryDB::new();
+		let mut root = TrieH256::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			for (key, value) in transaction {
+				trie.insert(&key, &value).expect("inserting into a fresh trie never fails");
+			}
+		}
+
+		let nodes = db.drain().into_iter().map(|(hash, (value, _rc))| (hash, value)).collect();
+		storage.insert(block, root, nodes);
+		root
+	}
+
+	#[test]
+	fn compute_changes_trie_root_round_trips_through_key_changes_across_a_digest_boundary() {
+		let config = config();
+		let storage = Arc::new(InMemoryStorage::new());
+
+		for block in 1..=4u64 {
+			let changes = if block == 1 || block == 4 {
+				changes_for(b"key")
+			} else {
+				changes_for(b"other")
+			};
+
+			// `key` changing again in the digest-boundary block's own
+			// execution (on top of its earlier change at block 1, picked up
+			// by this digest) is exactly the shape that used to collide in
+			// the trie before `InputPair` entries were tagged by kind - see
+			// `input::InputKeyKind`.
+			let digest_storage = if block == 4 {
+				Some(storage.clone() as Arc<Storage>)
+			} else {
+				None
+			};
+
+			let (root, transaction) = compute_changes_trie_root(digest_storage, &config, block, &changes, None, None)
+				.expect("computing the changes trie root succeeds")
+				.expect("every block in this test changes or digests something");
+
+			let stored_root = store_transaction(&storage, block, transaction);
+			assert_eq!(TrieH256::from(root), stored_root);
+		}
+
+		let changes = key_changes(&config, &*storage, 1, 4, b"key").expect("key_changes succeeds");
+		assert_eq!(changes, vec![1]);
+	}
 }
@@ -0,0 +1,269 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! In-memory changes trie storage, and pruning of changes tries that have
+//! fallen out of the configured retention window.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use trie_backend::{DBValue, TrieH256};
+use changes_trie::{Configuration, Storage};
+use changes_trie::build_iterator::{digest_build_iterator, DigestItem};
+
+/// In-memory implementation of changes trie storage, reference-counting
+/// trie nodes so that `prune` can discard changes tries (and superseded
+/// digests) once they fall out of the retention window, without disturbing
+/// nodes a surviving digest still needs.
+#[derive(Default)]
+pub struct InMemoryStorage {
+	data: RwLock<InMemoryStorageData>,
+}
+
+#[derive(Default)]
+struct InMemoryStorageData {
+	roots: HashMap<u64, TrieH256>,
+	root_nodes: HashMap<TrieH256, Vec<TrieH256>>,
+	nodes: HashMap<TrieH256, (u32, DBValue)>,
+}
+
+impl InMemoryStorage {
+	/// Creates a new, empty in-memory changes trie storage.
+	pub fn new() -> Self {
+		InMemoryStorage::default()
+	}
+
+	/// Inserts the changes trie built for `block`, rooted at `root` and made
+	/// up of `nodes`. Every node's reference count is bumped by one; a node
+	/// shared with a trie that's already stored is kept alive for as long as
+	/// either trie still needs it.
+	pub fn insert(&self, block: u64, root: TrieH256, nodes: Vec<(TrieH256, DBValue)>) {
+		let mut data = self.data.write().expect("InMemoryStorage lock is not poisoned");
+		data.roots.insert(block, root);
+		let mut hashes = Vec::with_capacity(nodes.len());
+		for (hash, value) in nodes {
+			hashes.push(hash);
+			data.nodes.entry(hash).or_insert_with(|| (0, value)).0 += 1;
+		}
+		data.root_nodes.insert(root, hashes);
+	}
+}
+
+impl Storage for InMemoryStorage {
+	fn root(&self, block: u64) -> Result<Option<TrieH256>, String> {
+		Ok(self.data.read().expect("InMemoryStorage lock is not poisoned").roots.get(&block).cloned())
+	}
+
+	fn get(&self, key: &TrieH256) -> Result<Option<DBValue>, String> {
+		Ok(self.data.read().expect("InMemoryStorage lock is not poisoned")
+			.nodes.get(key).map(|&(_, ref value)| value.clone()))
+	}
+
+	fn trie_nodes_for_root(&self, root: &TrieH256) -> Result<Vec<TrieH256>, String> {
+		Ok(self.data.read().expect("InMemoryStorage lock is not poisoned")
+			.root_nodes.get(root).cloned().unwrap_or_default())
+	}
+
+	fn remove_trie_node(&self, node: &TrieH256) -> Result<(), String> {
+		let mut data = self.data.write().expect("InMemoryStorage lock is not poisoned");
+		let drop_node = match data.nodes.get_mut(node) {
+			Some(entry) => {
+				entry.0 = entry.0.saturating_sub(1);
+				entry.0 == 0
+			},
+			None => return Ok(()),
+		};
+		if drop_node {
+			data.nodes.remove(node);
+		}
+		Ok(())
+	}
+}
+
+/// Prunes changes tries, keeping only the last `min_blocks_to_keep` blocks
+/// (relative to `current_block`) queryable at full, per-block granularity.
+///
+/// Walks the same digest hierarchy `changes_iterator` and `build` use: once
+/// a digest is found to be wholly outside the retention window, its own
+/// root is left in place (it's what still answers queries for that period),
+/// but every per-block trie and lower-level digest that only existed to
+/// build it is discarded. Nodes are removed by reference count, so nodes a
+/// surviving digest shares with a pruned one are left untouched.
+pub fn prune<S: Storage>(
+	config: &Configuration,
+	storage: &S,
+	min_blocks_to_keep: u64,
+	current_block: u64,
+) -> Result<(), String> {
+	if current_block <= min_blocks_to_keep {
+		return Ok(());
+	}
+	let prune_up_to = current_block - min_blocks_to_keep;
+
+	for item in digest_build_iterator(config, 1, prune_up_to) {
+		match item {
+			// A bare `Block` at the top level isn't covered by any digest -
+			// either digests are disabled, or it's one of the trailing blocks
+			// before the next digest boundary - so nothing has subsumed it yet
+			// and it must be left alone.
+			DigestItem::Block(_) => {},
+			DigestItem::Digest { level, digest_interval, block } => {
+				let span_begin = block + 1 - digest_interval;
+				prune_span(storage, config, level, span_begin, block.saturating_sub(1))?;
+			},
+		}
+	}
+	Ok(())
+}
+
+/// Removes everything that only existed to build a just-finalized digest:
+/// the per-block tries and lower-level digests covering `[span_begin,
+/// span_end]`. The finalized digest's own root (covering the full span,
+/// including its last block) is left untouched by the caller.
+fn prune_span<S: Storage>(
+	storage: &S,
+	config: &Configuration,
+	level: u8,
+	span_begin: u64,
+	span_end: u64,
+) -> Result<(), String> {
+	if span_begin > span_end {
+		return Ok(());
+	}
+
+	if level <= 1 {
+		for block in span_begin..=span_end {
+			remove_root(storage, block)?;
+		}
+		return Ok(());
+	}
+
+	for item in digest_build_iterator(config, span_begin, span_end) {
+		match item {
+			DigestItem::Block(block) => remove_root(storage, block)?,
+			DigestItem::Digest { level: lower_level, digest_interval, block } => {
+				remove_root(storage, block)?;
+				let lower_span_begin = block + 1 - digest_interval;
+				prune_span(storage, config, lower_level, lower_span_begin, block.saturating_sub(1))?;
+			},
+		}
+	}
+	Ok(())
+}
+
+fn remove_root<S: Storage>(storage: &S, block: u64) -> Result<(), String> {
+	let root = match storage.root(block)? {
+		Some(root) => root,
+		None => return Ok(()),
+	};
+
+	for node in storage.trie_nodes_for_root(&root)? {
+		storage.remove_trie_node(&node)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn node(byte: u8) -> (TrieH256, DBValue) {
+		(TrieH256::from([byte; 32]), vec![byte].into())
+	}
+
+	fn config() -> Configuration {
+		Configuration { digest_interval: 4, digest_levels: 1, skewed_digest: None }
+	}
+
+	#[test]
+	fn prune_leaves_trailing_not_yet_digested_blocks_untouched() {
+		let storage = InMemoryStorage::new();
+		let (hash1, u1) = node(1);
+		let (hash2, u2) = node(2);
+		let (hash3, u3) = node(3);
+		storage.insert(1, TrieH256::from([11; 32]), vec![(hash1, u1)]);
+		storage.insert(2, TrieH256::from([12; 32]), vec![(hash2, u2)]);
+		storage.insert(3, TrieH256::from([13; 32]), vec![(hash3, u3)]);
+
+		// blocks 1..3 don't complete a digest_interval = 4 span yet, so
+		// nothing has subsumed them - pruning must leave them alone.
+		prune(&config(), &storage, 0, 3).expect("prune succeeds");
+
+		assert!(storage.get(&hash1).unwrap().is_some());
+		assert!(storage.get(&hash2).unwrap().is_some());
+		assert!(storage.get(&hash3).unwrap().is_some());
+	}
+
+	#[test]
+	fn prune_removes_a_digested_span_but_keeps_nodes_shared_with_the_digest() {
+		let storage = InMemoryStorage::new();
+		let (shared_hash, shared_value) = node(0);
+		let (u1_hash, u1) = node(1);
+		let (u2_hash, u2) = node(2);
+		let (u3_hash, u3) = node(3);
+		let (digest_hash, digest_value) = node(4);
+
+		storage.insert(1, TrieH256::from([11; 32]), vec![(shared_hash, shared_value.clone()), (u1_hash, u1)]);
+		storage.insert(2, TrieH256::from([12; 32]), vec![(shared_hash, shared_value.clone()), (u2_hash, u2)]);
+		storage.insert(3, TrieH256::from([13; 32]), vec![(shared_hash, shared_value.clone()), (u3_hash, u3)]);
+		storage.insert(4, TrieH256::from([14; 32]), vec![(shared_hash, shared_value), (digest_hash, digest_value)]);
+
+		// blocks 1..=3 are exactly the span a level-1 digest at block 4 was
+		// built out of - they're subsumed and safe to discard.
+		prune(&config(), &storage, 0, 4).expect("prune succeeds");
+
+		assert!(storage.get(&u1_hash).unwrap().is_none());
+		assert!(storage.get(&u2_hash).unwrap().is_none());
+		assert!(storage.get(&u3_hash).unwrap().is_none());
+		// the digest's own root is left in place, and a node it shares with
+		// the pruned blocks survives by reference count.
+		assert!(storage.get(&shared_hash).unwrap().is_some());
+		assert!(storage.get(&digest_hash).unwrap().is_some());
+	}
+
+	#[test]
+	fn prune_removes_an_entire_span_across_a_two_level_digest_hierarchy() {
+		let storage = InMemoryStorage::new();
+		let config = Configuration { digest_interval: 4, digest_levels: 2, skewed_digest: None };
+		let (shared_hash, shared_value) = node(0);
+
+		let mut unique_hashes = Vec::new();
+		for block in 1..=16u8 {
+			let (u_hash, u_value) = node(block);
+			unique_hashes.push(u_hash);
+			storage.insert(
+				block as u64,
+				TrieH256::from([block; 32]),
+				vec![(shared_hash, shared_value.clone()), (u_hash, u_value)],
+			);
+		}
+
+		// blocks 1..=15 are exactly the span a level-2 digest at block 16 was
+		// built out of, via the intermediate level-1 digests at 4/8/12 - all
+		// of them (digests included) are subsumed and safe to discard,
+		// leaving only the top-level digest's own root.
+		prune(&config, &storage, 0, 16).expect("prune succeeds");
+
+		for (index, hash) in unique_hashes.iter().enumerate() {
+			let block = index + 1;
+			if block == 16 {
+				assert!(storage.get(hash).unwrap().is_some(), "block 16's own node must survive");
+			} else {
+				assert!(storage.get(hash).unwrap().is_none(), "block {}'s node must be pruned", block);
+			}
+		}
+		assert!(storage.get(&shared_hash).unwrap().is_some());
+	}
+}
@@ -0,0 +1,206 @@
+// Copyright 2017 Parity Technologies (UK) Ltd.
+// This file is part of Substrate.
+
+// Substrate is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Substrate is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Storage proof primitives.
+//!
+//! A storage proof is a set of trie nodes that is sufficient to answer a
+//! single storage read (or a handful of reads) against a trusted state
+//! root, without requiring access to the full trie backend. This is the
+//! primitive bridge/light-client code needs to trust a storage value that
+//! came from a header's state root: `prove_read` records the nodes touched
+//! while performing a real read, and `StorageProofChecker` re-checks that
+//! same read later using only the recorded nodes, so provers and verifiers
+//! always walk identical code.
+
+use hashdb::{HashDB, DBValue};
+use memorydb::MemoryDB;
+use patricia_trie::{TrieDB, Trie, Recorder};
+use trie_backend::TrieH256;
+use Storage as TrieStorage;
+
+/// Error that can occur in storage proof generation or checking.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+	/// Trie lookup error.
+	Trie(String),
+	/// The proof doesn't contain a node that's needed to answer the query.
+	StorageValueUnavailable,
+	/// The nodes in the proof don't hash up to the claimed storage root.
+	StorageRootMismatch,
+}
+
+impl ::std::fmt::Display for Error {
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+		match *self {
+			Error::Trie(ref e) => write!(f, "trie error: {}", e),
+			Error::StorageValueUnavailable => write!(f, "storage value unavailable, missing proof node"),
+			Error::StorageRootMismatch => write!(f, "storage proof doesn't match storage root"),
+		}
+	}
+}
+
+/// Checks storage read proofs against a known-good state root.
+///
+/// Builds an in-memory trie out of the proof nodes alone, so `read_value`
+/// never needs to touch a real database - only the nodes that were
+/// recorded while producing the proof are visible to it.
+pub struct StorageProofChecker {
+	root: TrieH256,
+	db: MemoryDB,
+}
+
+impl StorageProofChecker {
+	/// Constructs a new storage proof checker.
+	///
+	/// Fails immediately if `root` isn't actually present among the hashes
+	/// of the supplied proof nodes.
+	pub fn new(root: TrieH256, proof: Vec<Vec<u8>>) -> Result<Self, Error> {
+		let mut db = MemoryDB::new();
+		for item in proof {
+			db.insert(&item);
+		}
+
+		if !db.contains(&root) {
+			return Err(Error::StorageRootMismatch);
+		}
+
+		Ok(StorageProofChecker { root, db })
+	}
+
+	/// Reads a value from the proof.
+	///
+	/// Returns `Ok(None)` when the proof demonstrates the key is absent
+	/// from the trie, and `Err(StorageValueUnavailable)` when the proof
+	/// simply doesn't contain enough nodes to resolve the key either way.
+	pub fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		let trie = TrieDB::new(&self.db, &self.root).map_err(|e| Error::Trie(format!("{}", e)))?;
+		trie.get(key)
+			.map_err(|e| match *e {
+				// a key the proof shows to be absent is `Ok(None)` and never
+				// gets here; every lookup error - a missing node included - is
+				// reported as an incomplete proof.
+				_ => Error::StorageValueUnavailable,
+			})
+			.map(|value| value.map(|value| value.to_vec()))
+	}
+}
+
+/// Adapts a `Storage` (lookup-by-hash) into something `TrieDB` can read,
+/// so a proof can be recorded straight off a real backend instead of first
+/// copying it into a `MemoryDB`.
+struct Ephemeral<'a, S: 'a> {
+	storage: &'a S,
+}
+
+impl<'a, S: 'a + TrieStorage> HashDB for Ephemeral<'a, S> {
+	fn get(&self, key: &TrieH256) -> Option<DBValue> {
+		self.storage.get(key).unwrap_or(None)
+	}
+
+	fn contains(&self, key: &TrieH256) -> bool {
+		self.get(key).is_some()
+	}
+
+	fn insert(&mut self, _value: &[u8]) -> TrieH256 {
+		unreachable!("Ephemeral storage proof backend is read-only")
+	}
+
+	fn emplace(&mut self, _key: TrieH256, _value: DBValue) {
+		unreachable!("Ephemeral storage proof backend is read-only")
+	}
+
+	fn remove(&mut self, _key: &TrieH256) {
+		unreachable!("Ephemeral storage proof backend is read-only")
+	}
+}
+
+/// Performs a storage read against `storage` at `root`, recording every
+/// trie node visited along the way.
+///
+/// Returns the value that was read together with the recorded nodes, which
+/// is exactly the proof a `StorageProofChecker` needs to repeat the check
+/// without `storage`.
+pub fn prove_read<S: TrieStorage>(storage: &S, root: TrieH256, key: &[u8])
+	-> Result<(Option<Vec<u8>>, Vec<Vec<u8>>), Error>
+{
+	let eph = Ephemeral { storage };
+	let mut recorder = Recorder::new();
+
+	let result = {
+		let trie = TrieDB::new(&eph, &root).map_err(|e| Error::Trie(format!("{}", e)))?;
+		trie.get_with(key, &mut recorder).map_err(|e| Error::Trie(format!("{}", e)))?
+	};
+
+	let proof = recorder.drain().into_iter().map(|record| record.data).collect();
+	Ok((result.map(|value| value.to_vec()), proof))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+	use patricia_trie::{TrieDBMut, TrieMut};
+
+	/// A `TrieStorage` backed by a plain map of already-built trie nodes.
+	struct MapStorage(HashMap<TrieH256, DBValue>);
+
+	impl TrieStorage for MapStorage {
+		fn get(&self, key: &TrieH256) -> Result<Option<DBValue>, String> {
+			Ok(self.0.get(key).cloned())
+		}
+	}
+
+	fn build_trie(pairs: &[(&[u8], &[u8])]) -> (TrieH256, MapStorage) {
+		let mut db = MemoryDB::new();
+		let mut root = TrieH256::default();
+		{
+			let mut trie = TrieDBMut::new(&mut db, &mut root);
+			for &(key, value) in pairs {
+				trie.insert(key, value).expect("inserting into a fresh trie never fails");
+			}
+		}
+
+		let nodes = db.drain().into_iter().map(|(hash, (value, _rc))| (hash, value)).collect();
+		(root, MapStorage(nodes))
+	}
+
+	#[test]
+	fn prove_read_roundtrips_through_storage_proof_checker() {
+		// values long enough that each leaf is stored as its own node rather
+		// than inlined into the branch above it.
+		let value1 = b"value1 - long enough to be stored as its own node";
+		let value2 = b"value2 - long enough to be stored as its own node";
+		let (root, storage) = build_trie(&[(b"key1", value1), (b"key2", value2)]);
+
+		let (value, proof) = prove_read(&storage, root, b"key1").expect("key1 is in the trie");
+		assert_eq!(value, Some(value1.to_vec()));
+
+		let checker = StorageProofChecker::new(root, proof).expect("proof contains the claimed root");
+		assert_eq!(checker.read_value(b"key1").expect("key1 is provable"), Some(value1.to_vec()));
+		// a key that was never looked up (and so never recorded) can't be
+		// resolved either way from this proof.
+		assert_eq!(checker.read_value(b"key2"), Err(Error::StorageValueUnavailable));
+	}
+
+	#[test]
+	fn storage_proof_checker_rejects_a_root_the_proof_does_not_contain() {
+		let (root, storage) = build_trie(&[(b"key", b"value")]);
+		let (_, proof) = prove_read(&storage, root, b"key").expect("key is in the trie");
+
+		let wrong_root = TrieH256::from([0xffu8; 32]);
+		assert_eq!(StorageProofChecker::new(wrong_root, proof).unwrap_err(), Error::StorageRootMismatch);
+	}
+}